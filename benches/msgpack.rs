@@ -0,0 +1,228 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nvim_sys::{read_array_len, skip_value, FromMsgpack, FromMsgpackError, Session, ToMsgpack};
+use std::io::{self, Cursor, Read};
+
+const LINES: usize = 10_000;
+
+/// Grid size of a 4k display with an 8x16 cell font.
+const COLUMNS: usize = 480;
+const ROWS: usize = 135;
+
+fn lines() -> Vec<String> {
+    (0..LINES)
+        .map(|i| format!("{i:>6}    let value = compute(input, options).unwrap_or_default();"))
+        .collect()
+}
+
+fn encode_lines(c: &mut Criterion) {
+    let lines = lines();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let mut group = c.benchmark_group("buf_set_lines");
+    group.throughput(Throughput::Elements(LINES as u64));
+    let mut w = Vec::with_capacity(1 << 20);
+    group.bench_function("per_line", |b| {
+        b.iter(|| {
+            w.clear();
+            rmp::encode::write_array_len(&mut w, lines.len() as u32).unwrap();
+            for &line in lines.iter() {
+                line.to_msgpack(&mut w).unwrap();
+            }
+        })
+    });
+    group.bench_function("slice", |b| {
+        b.iter(|| {
+            w.clear();
+            lines.as_slice().to_msgpack(&mut w).unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn decode_lines(c: &mut Criterion) {
+    let mut bytes = vec![];
+    rmp::encode::write_array_len(&mut bytes, LINES as u32).unwrap();
+    for line in lines() {
+        rmp::encode::write_str(&mut bytes, &line).unwrap();
+    }
+
+    let mut group = c.benchmark_group("buf_get_lines");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("decode", |b| {
+        b.iter(|| Vec::<String>::from_msgpack(&mut bytes.as_slice()).unwrap())
+    });
+    group.finish();
+}
+
+/// A full-screen redraw notification.
+fn redraw() -> Vec<u8> {
+    let w = &mut vec![];
+    rmp::encode::write_array_len(w, 3).unwrap();
+    rmp::encode::write_uint(w, 2).unwrap();
+    rmp::encode::write_str(w, "redraw").unwrap();
+    rmp::encode::write_array_len(w, 1).unwrap();
+    rmp::encode::write_array_len(w, ROWS as u32 + 1).unwrap();
+    rmp::encode::write_str(w, "grid_line").unwrap();
+    for row in 0..ROWS {
+        rmp::encode::write_array_len(w, 5).unwrap();
+        rmp::encode::write_uint(w, 1).unwrap();
+        rmp::encode::write_uint(w, row as u64).unwrap();
+        rmp::encode::write_uint(w, 0).unwrap();
+        rmp::encode::write_array_len(w, COLUMNS as u32).unwrap();
+        for column in 0..COLUMNS {
+            // Highlight changes every few cells, as with syntax highlighting
+            if column % 4 == 0 {
+                rmp::encode::write_array_len(w, 2).unwrap();
+                rmp::encode::write_str(w, "x").unwrap();
+                rmp::encode::write_uint(w, (column % 200) as u64).unwrap();
+            } else {
+                rmp::encode::write_array_len(w, 1).unwrap();
+                rmp::encode::write_str(w, "x").unwrap();
+            }
+        }
+        rmp::encode::write_bool(w, false).unwrap();
+    }
+    w.to_vec()
+}
+
+/// A full-screen redraw notification followed by the response to a request.
+fn redraw_then_response() -> Vec<u8> {
+    let mut w = redraw();
+    rmp::encode::write_array_len(&mut w, 4).unwrap();
+    rmp::encode::write_uint(&mut w, 1).unwrap();
+    rmp::encode::write_uint(&mut w, 0).unwrap();
+    rmp::encode::write_nil(&mut w).unwrap();
+    rmp::encode::write_sint(&mut w, 42).unwrap();
+    w
+}
+
+struct Cell {
+    _text: String,
+    _hl_id: Option<i64>,
+    _repeat: Option<i64>,
+}
+
+impl FromMsgpack for Cell {
+    fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
+        let len = read_array_len(r)?;
+        Ok(Self {
+            _text: String::from_msgpack(r)?,
+            _hl_id: (len > 1).then(|| i64::from_msgpack(r)).transpose()?,
+            _repeat: (len > 2).then(|| i64::from_msgpack(r)).transpose()?,
+        })
+    }
+}
+
+struct GridLine {
+    _grid: i64,
+    _row: i64,
+    _col_start: i64,
+    _cells: Vec<Cell>,
+    _wrap: bool,
+}
+
+impl FromMsgpack for GridLine {
+    fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
+        read_array_len(r)?;
+        Ok(Self {
+            _grid: i64::from_msgpack(r)?,
+            _row: i64::from_msgpack(r)?,
+            _col_start: i64::from_msgpack(r)?,
+            _cells: Vec::from_msgpack(r)?,
+            _wrap: bool::from_msgpack(r)?,
+        })
+    }
+}
+
+/// Decodes a redraw notification made up of `grid_line` events.
+fn decode_redraw(r: &mut impl Read) -> Result<Vec<GridLine>, FromMsgpackError> {
+    read_array_len(r)?;
+    i64::from_msgpack(r)?;
+    String::from_msgpack(r)?;
+    let mut lines = vec![];
+    for _ in 0..read_array_len(r)? {
+        let len = read_array_len(r)?;
+        String::from_msgpack(r)?;
+        for _ in 1..len {
+            lines.push(GridLine::from_msgpack(r)?);
+        }
+    }
+    Ok(lines)
+}
+
+/// Skips a value by matching on [`rmp::Marker`], as a baseline for the
+/// lookup table behind [`skip_value`].
+fn skip_value_match(r: &mut impl Read) -> Result<(), FromMsgpackError> {
+    use rmp::Marker;
+    fn read_len(r: &mut impl Read, width: usize) -> io::Result<u64> {
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf[4 - width..])?;
+        Ok(u32::from_be_bytes(buf) as u64)
+    }
+
+    let (bytes, values) = match rmp::decode::read_marker(r)? {
+        Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False => {
+            (0, 0)
+        }
+        Marker::U8 | Marker::I8 => (1, 0),
+        Marker::U16 | Marker::I16 => (2, 0),
+        Marker::U32 | Marker::I32 | Marker::F32 => (4, 0),
+        Marker::U64 | Marker::I64 | Marker::F64 => (8, 0),
+        Marker::FixStr(len) => (len as u64, 0),
+        Marker::Str8 | Marker::Bin8 => (read_len(r, 1)?, 0),
+        Marker::Str16 | Marker::Bin16 => (read_len(r, 2)?, 0),
+        Marker::Str32 | Marker::Bin32 => (read_len(r, 4)?, 0),
+        Marker::FixArray(len) => (0, len as u64),
+        Marker::Array16 => (0, read_len(r, 2)?),
+        Marker::Array32 => (0, read_len(r, 4)?),
+        Marker::FixMap(len) => (0, len as u64 * 2),
+        Marker::Map16 => (0, read_len(r, 2)? * 2),
+        Marker::Map32 => (0, read_len(r, 4)? * 2),
+        Marker::FixExt1 => (2, 0),
+        Marker::FixExt2 => (3, 0),
+        Marker::FixExt4 => (5, 0),
+        Marker::FixExt8 => (9, 0),
+        Marker::FixExt16 => (17, 0),
+        Marker::Ext8 => (read_len(r, 1)? + 1, 0),
+        Marker::Ext16 => (read_len(r, 2)? + 1, 0),
+        Marker::Ext32 => (read_len(r, 4)? + 1, 0),
+        Marker::Reserved => panic!("reserved marker"),
+    };
+    io::copy(&mut r.by_ref().take(bytes), &mut io::sink())?;
+    for _ in 0..values {
+        skip_value_match(r)?;
+    }
+    Ok(())
+}
+
+fn redraw_batch(c: &mut Criterion) {
+    let bytes = redraw();
+    let mut group = c.benchmark_group("redraw");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("skip_match", |b| {
+        b.iter(|| skip_value_match(&mut bytes.as_slice()).unwrap())
+    });
+    group.bench_function("skip_table", |b| {
+        b.iter(|| skip_value(&mut bytes.as_slice()).unwrap())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| decode_redraw(&mut bytes.as_slice()).unwrap())
+    });
+
+    let bytes = redraw_then_response();
+    group.bench_function("session", |b| {
+        b.iter(|| {
+            let mut session = Session::new(Cursor::new(bytes.as_slice()), io::sink());
+            let result: i64 = session
+                .request("nvim_get_current_line", |w| {
+                    rmp::encode::write_array_len(w, 0)?;
+                    Ok(())
+                })
+                .unwrap();
+            result
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode_lines, decode_lines, redraw_batch);
+criterion_main!(benches);
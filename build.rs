@@ -6,7 +6,7 @@ use std::{
     fs::{self, File},
     io::{self, BufWriter, Write},
     path::{Path, Prefix},
-    process::{Command, Stdio},
+    process::{Command, ExitStatus, Stdio},
 };
 
 use rmp_serde::from_read;
@@ -21,23 +21,29 @@ macro_rules! warn {
     }
 }
 
+/// Path to the output of `nvim --api-info`, used instead of running nvim.
+const API_INFO_VAR: &str = "NVIM_SYS_API_INFO";
+
+/// Output of `nvim --api-info` shipped with the crate, relative to the
+/// manifest. Regenerate it with `update_api_info.sh`.
+const BUNDLED_API_INFO: &str = "api_info.mpack";
+
 #[derive(Debug, thiserror::Error)]
 enum MainError {
     #[error("{0}")]
     Io(#[from] io::Error),
     #[error("Missing nvim stdout")]
     NvimStdout,
+    #[error("nvim exited with {0}")]
+    NvimStatus(ExitStatus),
     #[error("{0}")]
     Rmp(#[from] rmp_serde::decode::Error),
 }
 
 fn main() -> Result<(), MainError> {
-    let mut nvim = Command::new("nvim")
-        .arg("--api-info")
-        .stdout(Stdio::piped())
-        .spawn()?;
-    let mut stdout = nvim.stdout.take().ok_or(MainError::NvimStdout)?;
-    let root: Root = from_read(stdout)?;
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed={API_INFO_VAR}");
+    let root = load_api_info()?.unwrap_or_default();
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let out_path = Path::new(&out_dir).join("nvim.rs");
@@ -45,15 +51,93 @@ fn main() -> Result<(), MainError> {
     let mut w = BufWriter::new(out_file);
     write_version(&mut w, &root.version)?;
     write_functions(&mut w, &root.functions)?;
-    println!("cargo:rerun-if-changed=build.rs");
     Ok(())
 }
 
+/// Reads the API description, or returns `None` when it is unavailable and an
+/// empty stub API surface should be generated instead.
+///
+/// Build scripts run on the host, so the host's nvim describes the API
+/// correctly even when cross-compiling. When it can't be run, as on docs.rs,
+/// the bundled description from [`BUNDLED_API_INFO`] is used.
+fn load_api_info() -> Result<Option<Root>, MainError> {
+    if let Some(path) = env::var_os(API_INFO_VAR) {
+        println!("cargo:rerun-if-changed={}", Path::new(&path).display());
+        return Ok(Some(from_read(File::open(path)?)?));
+    }
+
+    let target = env::var("TARGET").unwrap_or_default();
+    let host = env::var("HOST").unwrap_or_default();
+    let cross = target != host;
+
+    if env::var_os("DOCS_RS").is_none() {
+        match run_nvim() {
+            Ok(root) => {
+                if cross {
+                    warn!(
+                        "Cross-compiling from {host} to {target}, generating the API from the \
+                        host's nvim {}.{}.{}",
+                        root.version.major, root.version.minor, root.version.patch
+                    );
+                }
+                return Ok(Some(root));
+            }
+            Err(e) => warn!("Failed to run `nvim --api-info` ({e})"),
+        }
+    }
+
+    if cross {
+        warn!("Cross-compiling from {host} to {target} without a host nvim");
+    }
+
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let bundled = Path::new(&manifest_dir).join(BUNDLED_API_INFO);
+    // Watching a missing file would rerun the build script on every build
+    if bundled.exists() {
+        println!("cargo:rerun-if-changed={}", bundled.display());
+    }
+    match File::open(&bundled) {
+        Ok(file) => {
+            let root: Root = from_read(file)?;
+            let version = &root.version;
+            warn!(
+                "Using the bundled API from nvim {}.{}.{}. Install nvim or set {API_INFO_VAR} \
+                to the output of `nvim --api-info` to match your nvim.",
+                version.major, version.minor, version.patch
+            );
+            Ok(Some(root))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!(
+                "No nvim or bundled {BUNDLED_API_INFO} is available, so the generated API is \
+                empty. Install nvim or set {API_INFO_VAR} to the output of `nvim --api-info`."
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn run_nvim() -> Result<Root, MainError> {
+    let mut nvim = Command::new("nvim")
+        .arg("--api-info")
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = nvim.stdout.take().ok_or(MainError::NvimStdout)?;
+    let root = from_read(stdout);
+    let status = nvim.wait()?;
+    if !status.success() {
+        return Err(MainError::NvimStatus(status));
+    }
+    Ok(root?)
+}
+
 fn write_functions(dst: &mut impl Write, functions: &[Function]) -> io::Result<()> {
     // TODO: Method, since, deprecated since
     write!(
         dst,
         "pub mod functions {{
+        #[allow(unused_imports)]
         use super::{{Buffer, Window, Tabpage, Array, BasicType, Dictionary, Neovim}};"
     )?;
     for function in functions.iter() {
@@ -80,6 +164,10 @@ fn write_functions(dst: &mut impl Write, functions: &[Function]) -> io::Result<(
                 TypeName::FixedArray { size, type_name } => {
                     write!(dst, "[{}; {size}]", map_parameter_type_name(type_name))?
                 }
+                // Line arrays have a fast path for slices
+                TypeName::DynamicArray(type_name) if type_name == "String" => {
+                    write!(dst, "&[&str]")?
+                }
                 TypeName::DynamicArray(type_name) => write!(
                     dst,
                     "impl Iterator<Item = {}>",
@@ -193,7 +281,8 @@ fn write_version(dst: &mut impl Write, version: &Version) -> io::Result<()> {
 type ErrorTypes = HashMap<String, ErrorType>;
 type Types = HashMap<String, Type>;
 
-#[derive(Debug, Deserialize)]
+// The default is the stub API surface: version 0.0.0 with no functions.
+#[derive(Debug, Default, Deserialize)]
 struct Root {
     version: Version,
     error_types: ErrorTypes,
@@ -203,7 +292,7 @@ struct Root {
     ui_events: Vec<UiEvent>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct Version {
     api_compatible: i64,
     api_level: i64,
@@ -4,10 +4,14 @@ use rmp::{
 };
 use std::{
     collections::HashMap,
+    fmt,
     io::{self, Read, Write},
     string::FromUtf8Error,
 };
 
+mod session;
+pub use session::{RpcError, Session, SessionError};
+
 pub type Array = Vec<BasicType>;
 pub type Dictionary = HashMap<BasicType, BasicType>;
 
@@ -44,6 +48,8 @@ pub enum ToMsgpackError {
     Io(#[from] io::Error),
     #[error("{0}")]
     Rmp(#[from] ValueWriteError),
+    #[error("Length {0} exceeds the MsgPack maximum")]
+    Length(usize),
 }
 
 impl ToMsgpack for bool {
@@ -74,6 +80,39 @@ impl ToMsgpack for &str {
     }
 }
 
+/// Line arrays such as those passed to `nvim_buf_set_lines` can be large, so
+/// each string header is written with a single fixed-size call instead of
+/// going through [`rmp::encode::write_str`] per line.
+impl ToMsgpack for &[&str] {
+    fn to_msgpack(self, w: &mut impl Write) -> Result<(), ToMsgpackError> {
+        rmp::encode::write_array_len(w, msgpack_len(self.len())?)?;
+        for s in self {
+            write_str_header(w, msgpack_len(s.len())?)?;
+            w.write_all(s.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn msgpack_len(len: usize) -> Result<u32, ToMsgpackError> {
+    u32::try_from(len).map_err(|_| ToMsgpackError::Length(len))
+}
+
+fn write_str_header(w: &mut impl Write, len: u32) -> io::Result<()> {
+    match len {
+        0..=0x1f => w.write_all(&[0xa0 | len as u8]),
+        0x20..=0xff => w.write_all(&[0xd9, len as u8]),
+        0x100..=0xffff => {
+            let [a, b] = (len as u16).to_be_bytes();
+            w.write_all(&[0xda, a, b])
+        }
+        _ => {
+            let [a, b, c, d] = len.to_be_bytes();
+            w.write_all(&[0xdb, a, b, c, d])
+        }
+    }
+}
+
 pub struct MsgpackArrayWriter<T, I>
 where
     T: ToMsgpack,
@@ -137,11 +176,117 @@ pub enum FromMsgpackError {
     Io(#[from] io::Error),
     #[error("{0}")]
     String(#[from] FromUtf8Error),
-    #[error("Unexpected MsgPack type")]
+    #[error("Unexpected MsgPack type: expected {expected:?}, found {actual:?}")]
     Marker {
         expected: BasicTypeKind,
         actual: rmp::Marker,
     },
+    #[error("{error}, {context}")]
+    Context {
+        context: DecodeContext,
+        error: Box<FromMsgpackError>,
+    },
+}
+
+impl FromMsgpackError {
+    /// Records that the error occurred while decoding `segment`. Segments are
+    /// added from the innermost value outwards as the error propagates.
+    pub fn within(self, segment: PathSegment) -> Self {
+        let (mut context, error) = self.into_parts();
+        context.path.push(segment);
+        Self::Context { context, error }
+    }
+
+    /// Records where in the message being decoded the failing value starts.
+    /// Only the first offset recorded is kept.
+    pub fn at(self, offset: u64) -> Self {
+        let (mut context, error) = self.into_parts();
+        context.offset.get_or_insert(offset);
+        Self::Context { context, error }
+    }
+
+    /// Where decoding failed, if known.
+    pub fn context(&self) -> Option<&DecodeContext> {
+        match self {
+            Self::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The underlying error, without its context.
+    pub fn root(&self) -> &Self {
+        match self {
+            Self::Context { error, .. } => error,
+            _ => self,
+        }
+    }
+
+    /// How many bytes of the failing value had been read when decoding
+    /// failed, where that is known. Type errors are raised just after reading
+    /// the marker.
+    pub(crate) fn consumed(&self) -> u64 {
+        match self.root() {
+            Self::Marker { .. } => 1,
+            _ => 0,
+        }
+    }
+
+    fn into_parts(self) -> (DecodeContext, Box<Self>) {
+        match self {
+            Self::Context { context, error } => (context, error),
+            error => (DecodeContext::default(), Box::new(error)),
+        }
+    }
+}
+
+/// Where in the stream and in a nested value a decode error occurred.
+#[derive(Debug, Default)]
+pub struct DecodeContext {
+    /// The byte offset of the failing value from the start of the message.
+    /// For truncated input or invalid UTF-8, this is where reading stopped.
+    pub offset: Option<u64>,
+    /// The values being decoded, innermost first.
+    pub path: Vec<PathSegment>,
+}
+
+impl fmt::Display for DecodeContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        if let Some((first, rest)) = self.path.split_first() {
+            write!(f, "while decoding {first}")?;
+            for segment in rest {
+                write!(f, " of {segment}")?;
+            }
+            separator = " ";
+        }
+        if let Some(offset) = self.offset {
+            write!(f, "{separator}at byte {offset} of the message")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PathSegment {
+    /// An element of an array.
+    Element(usize),
+    /// The key of a dictionary entry.
+    Key(usize),
+    /// The value of a dictionary entry.
+    Value(usize),
+    /// The result returned for a call to the named method.
+    Result(String),
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Element(i) => write!(f, "element {i}"),
+            Self::Key(i) => write!(f, "the key of entry {i}"),
+            Self::Value(i) => write!(f, "the value of entry {i}"),
+            Self::Result(method) => write!(f, "the result of {method}"),
+        }
+    }
 }
 
 impl From<MarkerReadError<io::Error>> for FromMsgpackError {
@@ -152,62 +297,39 @@ impl From<MarkerReadError<io::Error>> for FromMsgpackError {
 
 impl FromMsgpack for bool {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        match rmp::decode::read_marker(r)? {
-            rmp::Marker::True => Ok(true),
-            rmp::Marker::False => Ok(false),
-            marker => Err(FromMsgpackError::Marker {
-                expected: BasicTypeKind::Boolean,
-                actual: marker,
-            }),
+        match read_format(r)? {
+            (_, Format::Boolean(b)) => Ok(b),
+            (byte, _) => Err(unexpected(BasicTypeKind::Boolean, byte)),
         }
     }
 }
 
 impl FromMsgpack for i64 {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        match rmp::decode::read_marker(r)? {
-            rmp::Marker::U8 => Ok(rmp::decode::read_u8(r)? as i64),
-            rmp::Marker::U16 => Ok(rmp::decode::read_u16(r)? as i64),
-            rmp::Marker::U32 => Ok(rmp::decode::read_u32(r)? as i64),
-            rmp::Marker::U64 => Ok(rmp::decode::read_u64(r)? as i64),
-            rmp::Marker::I8 => Ok(rmp::decode::read_i8(r)? as i64),
-            rmp::Marker::I16 => Ok(rmp::decode::read_i16(r)? as i64),
-            rmp::Marker::I32 => Ok(rmp::decode::read_i32(r)? as i64),
-            rmp::Marker::I64 => Ok(rmp::decode::read_i64(r)? as i64),
-            marker => Err(FromMsgpackError::Marker {
-                expected: BasicTypeKind::Integer,
-                actual: marker,
-            }),
+        match read_format(r)? {
+            (_, Format::FixInt(n)) => Ok(n as i64),
+            (_, Format::Uint(width)) => Ok(read_uint(r, width)? as i64),
+            (_, Format::Int(width)) => Ok(read_int(r, width)?),
+            (byte, _) => Err(unexpected(BasicTypeKind::Integer, byte)),
         }
     }
 }
 
 impl FromMsgpack for f64 {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        match rmp::decode::read_marker(r)? {
-            rmp::Marker::F32 => Ok(rmp::decode::read_f32(r)? as f64),
-            rmp::Marker::F64 => Ok(rmp::decode::read_f64(r)? as f64),
-            marker => Err(FromMsgpackError::Marker {
-                expected: BasicTypeKind::Float,
-                actual: marker,
-            }),
+        match read_format(r)? {
+            (_, Format::F32) => Ok(f32::from_bits(read_u32(r)?) as f64),
+            (_, Format::F64) => Ok(f64::from_bits(read_u64(r)?)),
+            (byte, _) => Err(unexpected(BasicTypeKind::Float, byte)),
         }
     }
 }
 
 impl FromMsgpack for String {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        let len = match rmp::decode::read_marker(r)? {
-            rmp::Marker::FixStr(len) => len as usize,
-            rmp::Marker::Str8 => read_u8(r)? as usize,
-            rmp::Marker::Str16 => read_u16(r)? as usize,
-            rmp::Marker::Str32 => read_u32(r)? as usize,
-            marker => {
-                return Err(FromMsgpackError::Marker {
-                    expected: BasicTypeKind::String,
-                    actual: marker,
-                })
-            }
+        let len = match read_format(r)? {
+            (_, Format::Str(len)) => read_len(r, len)? as usize,
+            (byte, _) => return Err(unexpected(BasicTypeKind::String, byte)),
         };
 
         let mut buf = vec![0; len];
@@ -221,19 +343,10 @@ where
     T: FromMsgpack,
 {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        let len = match rmp::decode::read_marker(r)? {
-            rmp::Marker::FixArray(len) => len as usize,
-            rmp::Marker::Array16 => read_u16(r)? as usize,
-            rmp::Marker::Array32 => read_u32(r)? as usize,
-            marker => {
-                return Err(FromMsgpackError::Marker {
-                    expected: BasicTypeKind::Array,
-                    actual: marker,
-                })
-            }
-        };
-
-        (0..len).map(|_| T::from_msgpack(r)).collect()
+        let len = read_array_len(r)?;
+        (0..len)
+            .map(|i| T::from_msgpack(r).map_err(|e| e.within(PathSegment::Element(i))))
+            .collect()
     }
 }
 
@@ -243,43 +356,207 @@ where
     V: FromMsgpack,
 {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        let len = match rmp::decode::read_marker(r)? {
-            rmp::Marker::FixMap(len) => len as usize,
-            rmp::Marker::Map16 => read_u16(r)? as usize,
-            rmp::Marker::Map32 => read_u32(r)? as usize,
-            marker => {
-                return Err(FromMsgpackError::Marker {
-                    expected: BasicTypeKind::Dictionary,
-                    actual: marker,
-                })
-            }
-        };
-
+        let len = read_map_len(r)?;
         (0..len)
-            .map(|_| -> Result<_, _> { Ok((K::from_msgpack(r)?, V::from_msgpack(r)?)) })
+            .map(|i| -> Result<_, _> {
+                let k = K::from_msgpack(r).map_err(|e| e.within(PathSegment::Key(i)))?;
+                let v = V::from_msgpack(r).map_err(|e| e.within(PathSegment::Value(i)))?;
+                Ok((k, v))
+            })
             .collect()
     }
 }
 
 impl FromMsgpack for Buffer {
     fn from_msgpack(r: &mut impl Read) -> Result<Self, FromMsgpackError> {
-        match rmp::decode::read_marker(r)? {
-            rmp::Marker::FixExt1 => todo!(),
-            rmp::Marker::FixExt2 => todo!(),
-            rmp::Marker::FixExt4 => todo!(),
-            rmp::Marker::FixExt8 => todo!(),
-            rmp::Marker::FixExt16 => todo!(),
-            rmp::Marker::Ext8 => todo!(),
-            rmp::Marker::Ext16 => todo!(),
-            rmp::Marker::Ext32 => todo!(),
-            marker => Err(FromMsgpackError::Marker {
-                expected: BasicTypeKind::Object,
-                actual: marker,
-            }),
+        match read_format(r)? {
+            (_, Format::Ext(_)) => todo!(),
+            (byte, _) => Err(unexpected(BasicTypeKind::Object, byte)),
         }
     }
 }
 
+/// Reads the header of an array, returning its number of elements.
+pub fn read_array_len(r: &mut impl Read) -> Result<usize, FromMsgpackError> {
+    match read_format(r)? {
+        (_, Format::Array(len)) => Ok(read_len(r, len)? as usize),
+        (byte, _) => Err(unexpected(BasicTypeKind::Array, byte)),
+    }
+}
+
+/// Reads the header of a map, returning its number of entries.
+pub fn read_map_len(r: &mut impl Read) -> Result<usize, FromMsgpackError> {
+    match read_format(r)? {
+        (_, Format::Map(len)) => Ok(read_len(r, len)? as usize),
+        (byte, _) => Err(unexpected(BasicTypeKind::Dictionary, byte)),
+    }
+}
+
+/// Reads and discards a single MsgPack value of any type.
+pub fn skip_value(r: &mut impl Read) -> Result<(), FromMsgpackError> {
+    let mut remaining: u64 = 1;
+    while remaining > 0 {
+        remaining -= 1;
+        match read_format(r)? {
+            (_, Format::FixInt(_) | Format::Nil | Format::Boolean(_)) => {}
+            (_, Format::Uint(width) | Format::Int(width)) => skip_bytes(r, width as u64)?,
+            (_, Format::F32) => skip_bytes(r, 4)?,
+            (_, Format::F64) => skip_bytes(r, 8)?,
+            (_, Format::Str(len) | Format::Bin(len)) => {
+                let len = read_len(r, len)?;
+                skip_bytes(r, len)?;
+            }
+            (_, Format::Ext(len)) => {
+                // The payload is preceded by the extension type
+                let len = read_len(r, len)?;
+                skip_bytes(r, len + 1)?;
+            }
+            (_, Format::Array(len)) => remaining += read_len(r, len)?,
+            (_, Format::Map(len)) => remaining += read_len(r, len)? * 2,
+            (byte, Format::Reserved) => return Err(unexpected(BasicTypeKind::Object, byte)),
+        }
+    }
+    Ok(())
+}
+
+fn skip_bytes(r: &mut impl Read, len: u64) -> io::Result<()> {
+    const SMALL: usize = 32;
+    // Compared as u64 so that large lengths can't truncate on 32-bit targets
+    if len <= SMALL as u64 {
+        let mut buf = [0; SMALL];
+        return r.read_exact(&mut buf[..len as usize]);
+    }
+
+    let skipped = io::copy(&mut r.by_ref().take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Where the length of a string, binary, array, map, or extension is stored.
+#[derive(Clone, Copy)]
+pub(crate) enum Len {
+    /// In the marker itself.
+    Fixed(u8),
+    /// In this many big-endian bytes following the marker.
+    Prefixed(u8),
+}
+
+/// What follows a marker byte.
+#[derive(Clone, Copy)]
+pub(crate) enum Format {
+    /// An integer stored in the marker itself.
+    FixInt(i8),
+    Nil,
+    Boolean(bool),
+    /// An unsigned integer this many bytes wide.
+    Uint(u8),
+    /// A signed integer this many bytes wide.
+    Int(u8),
+    F32,
+    F64,
+    Str(Len),
+    Bin(Len),
+    Array(Len),
+    Map(Len),
+    Ext(Len),
+    Reserved,
+}
+
+/// Dispatching on the raw marker byte through this table avoids decoding an
+/// [`rmp::Marker`] only to match on it again, which dominates decoding and
+/// skipping large redraw batches.
+static FORMATS: [Format; 256] = format_table();
+
+const fn format_table() -> [Format; 256] {
+    use Len::{Fixed, Prefixed};
+    let mut table = [Format::Reserved; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let byte = i as u8;
+        table[i] = match byte {
+            0x00..=0x7f | 0xe0..=0xff => Format::FixInt(byte as i8),
+            0x80..=0x8f => Format::Map(Fixed(byte & 0x0f)),
+            0x90..=0x9f => Format::Array(Fixed(byte & 0x0f)),
+            0xa0..=0xbf => Format::Str(Fixed(byte & 0x1f)),
+            0xc0 => Format::Nil,
+            0xc1 => Format::Reserved,
+            0xc2 => Format::Boolean(false),
+            0xc3 => Format::Boolean(true),
+            0xc4 => Format::Bin(Prefixed(1)),
+            0xc5 => Format::Bin(Prefixed(2)),
+            0xc6 => Format::Bin(Prefixed(4)),
+            0xc7 => Format::Ext(Prefixed(1)),
+            0xc8 => Format::Ext(Prefixed(2)),
+            0xc9 => Format::Ext(Prefixed(4)),
+            0xca => Format::F32,
+            0xcb => Format::F64,
+            0xcc => Format::Uint(1),
+            0xcd => Format::Uint(2),
+            0xce => Format::Uint(4),
+            0xcf => Format::Uint(8),
+            0xd0 => Format::Int(1),
+            0xd1 => Format::Int(2),
+            0xd2 => Format::Int(4),
+            0xd3 => Format::Int(8),
+            0xd4 => Format::Ext(Fixed(1)),
+            0xd5 => Format::Ext(Fixed(2)),
+            0xd6 => Format::Ext(Fixed(4)),
+            0xd7 => Format::Ext(Fixed(8)),
+            0xd8 => Format::Ext(Fixed(16)),
+            0xd9 => Format::Str(Prefixed(1)),
+            0xda => Format::Str(Prefixed(2)),
+            0xdb => Format::Str(Prefixed(4)),
+            0xdc => Format::Array(Prefixed(2)),
+            0xdd => Format::Array(Prefixed(4)),
+            0xde => Format::Map(Prefixed(2)),
+            0xdf => Format::Map(Prefixed(4)),
+        };
+        i += 1;
+    }
+    table
+}
+
+/// Reads a marker byte, returning it along with its format.
+pub(crate) fn read_format(r: &mut impl Read) -> io::Result<(u8, Format)> {
+    let byte = read_u8(r)?;
+    Ok((byte, FORMATS[byte as usize]))
+}
+
+#[cold]
+pub(crate) fn unexpected(expected: BasicTypeKind, byte: u8) -> FromMsgpackError {
+    FromMsgpackError::Marker {
+        expected,
+        actual: rmp::Marker::from_u8(byte),
+    }
+}
+
+pub(crate) fn read_len(r: &mut impl Read, len: Len) -> io::Result<u64> {
+    match len {
+        Len::Fixed(len) => Ok(len as u64),
+        Len::Prefixed(width) => read_uint(r, width),
+    }
+}
+
+fn read_uint(r: &mut impl Read, width: u8) -> io::Result<u64> {
+    match width {
+        1 => Ok(read_u8(r)? as u64),
+        2 => Ok(read_u16(r)? as u64),
+        4 => Ok(read_u32(r)? as u64),
+        _ => read_u64(r),
+    }
+}
+
+fn read_int(r: &mut impl Read, width: u8) -> io::Result<i64> {
+    match width {
+        1 => Ok(read_u8(r)? as i8 as i64),
+        2 => Ok(read_u16(r)? as i16 as i64),
+        4 => Ok(read_u32(r)? as i32 as i64),
+        _ => Ok(read_u64(r)? as i64),
+    }
+}
+
 fn read_u8(r: &mut impl Read) -> io::Result<u8> {
     let mut buf = [0; 1];
     r.read_exact(&mut buf)?;
@@ -298,6 +575,12 @@ fn read_u32(r: &mut impl Read) -> io::Result<u32> {
     Ok(u32::from_be_bytes(buf))
 }
 
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
 pub enum SpecialType {
     Buffer(Buffer),
     Window(Window),
@@ -353,7 +636,7 @@ fn write_special_type(w: &mut impl Write, type_id: i8, data: i64) -> Result<(),
     // TODO: Elide leading zero bytes
     let data = data.to_be_bytes();
     rmp::encode::write_ext_meta(w, 8, type_id)?;
-    w.write(&data)?;
+    w.write_all(&data)?;
     Ok(())
 }
 
@@ -375,7 +658,259 @@ pub trait Neovim {
         &mut self,
         method: &str,
         argument_writer: impl Fn(&mut Self::W),
-    ) -> Return;
+    ) -> Result<Return, SessionError>;
 }
 
 include!(concat!(env!("OUT_DIR"), "/nvim.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker_error() -> FromMsgpackError {
+        FromMsgpackError::Marker {
+            expected: BasicTypeKind::Integer,
+            actual: rmp::Marker::Null,
+        }
+    }
+
+    #[test]
+    fn context_path_is_innermost_first() {
+        let e = marker_error()
+            .within(PathSegment::Element(0))
+            .within(PathSegment::Element(1))
+            .within(PathSegment::Result("m".to_owned()));
+        assert_eq!(
+            e.context().unwrap().to_string(),
+            "while decoding element 0 of element 1 of the result of m"
+        );
+    }
+
+    #[test]
+    fn context_keeps_first_offset() {
+        let e = marker_error().at(3).within(PathSegment::Element(0)).at(10);
+        assert_eq!(e.context().unwrap().offset, Some(3));
+        assert_eq!(
+            e.context().unwrap().to_string(),
+            "while decoding element 0 at byte 3 of the message"
+        );
+    }
+
+    #[test]
+    fn context_root_is_original_error() {
+        let e = marker_error().within(PathSegment::Key(0)).at(1);
+        assert!(matches!(
+            e.root(),
+            FromMsgpackError::Marker {
+                expected: BasicTypeKind::Integer,
+                actual: rmp::Marker::Null,
+            }
+        ));
+        // Display already includes the root, so it isn't repeated as a source
+        assert!(std::error::Error::source(&e).is_none());
+        assert!(marker_error().context().is_none());
+        assert!(matches!(
+            marker_error().root(),
+            FromMsgpackError::Marker { .. }
+        ));
+    }
+
+    #[test]
+    fn dictionary_errors_name_key_or_value() {
+        let mut bad_key = vec![];
+        rmp::encode::write_map_len(&mut bad_key, 2).unwrap();
+        rmp::encode::write_str(&mut bad_key, "a").unwrap();
+        rmp::encode::write_sint(&mut bad_key, 1).unwrap();
+        rmp::encode::write_nil(&mut bad_key).unwrap();
+        let e = HashMap::<String, i64>::from_msgpack(&mut bad_key.as_slice()).unwrap_err();
+        assert_eq!(
+            e.context().unwrap().to_string(),
+            "while decoding the key of entry 1"
+        );
+
+        let mut bad_value = vec![];
+        rmp::encode::write_map_len(&mut bad_value, 1).unwrap();
+        rmp::encode::write_str(&mut bad_value, "a").unwrap();
+        rmp::encode::write_nil(&mut bad_value).unwrap();
+        let e = HashMap::<String, i64>::from_msgpack(&mut bad_value.as_slice()).unwrap_err();
+        assert_eq!(
+            e.context().unwrap().to_string(),
+            "while decoding the value of entry 0"
+        );
+    }
+
+    #[test]
+    fn str_header_matches_rmp() {
+        for len in [0, 31, 32, 255, 256, 65535, 65536] {
+            let mut expected = vec![];
+            rmp::encode::write_str_len(&mut expected, len).unwrap();
+            let mut actual = vec![];
+            write_str_header(&mut actual, len).unwrap();
+            assert_eq!(actual, expected, "length {len}");
+        }
+    }
+
+    #[test]
+    fn str_slice_matches_rmp() {
+        let lines: Vec<String> = [0, 31, 32, 255, 256, 65535, 65536]
+            .into_iter()
+            .map(|len| "a".repeat(len))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+        let mut expected = vec![];
+        rmp::encode::write_array_len(&mut expected, lines.len() as u32).unwrap();
+        for line in lines.iter() {
+            rmp::encode::write_str(&mut expected, line).unwrap();
+        }
+        let mut actual = vec![];
+        lines.as_slice().to_msgpack(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    /// Encodes a value with `write`, then checks that skipping it consumes
+    /// exactly its bytes.
+    fn assert_skips(write: impl FnOnce(&mut Vec<u8>)) {
+        const SENTINEL: u8 = 0xc3;
+        let mut input = vec![];
+        write(&mut input);
+        input.push(SENTINEL);
+        let mut r = input.as_slice();
+        skip_value(&mut r).unwrap();
+        assert_eq!(r, [SENTINEL]);
+    }
+
+    fn nils(w: &mut Vec<u8>, count: usize) {
+        for _ in 0..count {
+            rmp::encode::write_nil(w).unwrap();
+        }
+    }
+
+    #[test]
+    fn skips_scalars() {
+        assert_skips(|w| rmp::encode::write_pfix(w, 5).unwrap());
+        assert_skips(|w| rmp::encode::write_nfix(w, -5).unwrap());
+        assert_skips(|w| rmp::encode::write_nil(w).unwrap());
+        assert_skips(|w| rmp::encode::write_bool(w, false).unwrap());
+        assert_skips(|w| rmp::encode::write_bool(w, true).unwrap());
+        assert_skips(|w| rmp::encode::write_u8(w, 1).unwrap());
+        assert_skips(|w| rmp::encode::write_u16(w, 1).unwrap());
+        assert_skips(|w| rmp::encode::write_u32(w, 1).unwrap());
+        assert_skips(|w| rmp::encode::write_u64(w, 1).unwrap());
+        assert_skips(|w| rmp::encode::write_i8(w, -1).unwrap());
+        assert_skips(|w| rmp::encode::write_i16(w, -1).unwrap());
+        assert_skips(|w| rmp::encode::write_i32(w, -1).unwrap());
+        assert_skips(|w| rmp::encode::write_i64(w, -1).unwrap());
+        assert_skips(|w| rmp::encode::write_f32(w, 1.0).unwrap());
+        assert_skips(|w| rmp::encode::write_f64(w, 1.0).unwrap());
+    }
+
+    #[test]
+    fn skips_strings_and_binary() {
+        for len in [0, 31, 32, 255, 256, 65535, 65536] {
+            let data = vec![b'a'; len];
+            assert_skips(|w| {
+                rmp::encode::write_str_len(w, len as u32).unwrap();
+                w.extend_from_slice(&data);
+            });
+            assert_skips(|w| rmp::encode::write_bin(w, &data).unwrap());
+        }
+    }
+
+    #[test]
+    fn skips_extensions() {
+        for len in [1, 2, 4, 8, 16, 3, 255, 256, 65536] {
+            assert_skips(|w| {
+                rmp::encode::write_ext_meta(w, len, 0).unwrap();
+                w.resize(w.len() + len as usize, 0);
+            });
+        }
+    }
+
+    #[test]
+    fn skips_arrays_and_maps() {
+        for len in [0, 15, 16, 65536] {
+            assert_skips(|w| {
+                rmp::encode::write_array_len(w, len as u32).unwrap();
+                nils(w, len);
+            });
+            assert_skips(|w| {
+                rmp::encode::write_map_len(w, len as u32).unwrap();
+                nils(w, len * 2);
+            });
+        }
+    }
+
+    #[test]
+    fn skips_nested_values() {
+        assert_skips(|w| {
+            rmp::encode::write_array_len(w, 2).unwrap();
+            rmp::encode::write_map_len(w, 1).unwrap();
+            rmp::encode::write_str(w, "key").unwrap();
+            rmp::encode::write_ext_meta(w, 8, 0).unwrap();
+            w.extend_from_slice(&[0; 8]);
+            rmp::encode::write_array_len(w, 1).unwrap();
+            rmp::encode::write_array_len(w, 0).unwrap();
+        });
+    }
+
+    #[test]
+    fn rejects_reserved_marker() {
+        assert!(matches!(
+            skip_value(&mut [0xc1].as_slice()),
+            Err(FromMsgpackError::Marker {
+                actual: rmp::Marker::Reserved,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn formats_match_rmp_markers() {
+        use rmp::Marker as M;
+        for byte in 0..=u8::MAX {
+            let marker = M::from_u8(byte);
+            let matches = match (FORMATS[byte as usize], marker) {
+                (Format::FixInt(n), M::FixPos(m)) => n as u8 == m,
+                (Format::FixInt(n), M::FixNeg(m)) => n == m,
+                (Format::Nil, M::Null) => true,
+                (Format::Boolean(b), M::True | M::False) => b == (marker == M::True),
+                (Format::Uint(1), M::U8) | (Format::Uint(2), M::U16) => true,
+                (Format::Uint(4), M::U32) | (Format::Uint(8), M::U64) => true,
+                (Format::Int(1), M::I8) | (Format::Int(2), M::I16) => true,
+                (Format::Int(4), M::I32) | (Format::Int(8), M::I64) => true,
+                (Format::F32, M::F32) | (Format::F64, M::F64) => true,
+                (Format::Str(Len::Fixed(n)), M::FixStr(m)) => n == m,
+                (Format::Str(Len::Prefixed(1)), M::Str8) => true,
+                (Format::Str(Len::Prefixed(2)), M::Str16) => true,
+                (Format::Str(Len::Prefixed(4)), M::Str32) => true,
+                (Format::Bin(Len::Prefixed(1)), M::Bin8) => true,
+                (Format::Bin(Len::Prefixed(2)), M::Bin16) => true,
+                (Format::Bin(Len::Prefixed(4)), M::Bin32) => true,
+                (Format::Array(Len::Fixed(n)), M::FixArray(m)) => n == m,
+                (Format::Array(Len::Prefixed(2)), M::Array16) => true,
+                (Format::Array(Len::Prefixed(4)), M::Array32) => true,
+                (Format::Map(Len::Fixed(n)), M::FixMap(m)) => n == m,
+                (Format::Map(Len::Prefixed(2)), M::Map16) => true,
+                (Format::Map(Len::Prefixed(4)), M::Map32) => true,
+                (Format::Ext(Len::Fixed(1)), M::FixExt1) => true,
+                (Format::Ext(Len::Fixed(2)), M::FixExt2) => true,
+                (Format::Ext(Len::Fixed(4)), M::FixExt4) => true,
+                (Format::Ext(Len::Fixed(8)), M::FixExt8) => true,
+                (Format::Ext(Len::Fixed(16)), M::FixExt16) => true,
+                (Format::Ext(Len::Prefixed(1)), M::Ext8) => true,
+                (Format::Ext(Len::Prefixed(2)), M::Ext16) => true,
+                (Format::Ext(Len::Prefixed(4)), M::Ext32) => true,
+                (Format::Reserved, M::Reserved) => true,
+                _ => false,
+            };
+            assert!(matches, "{byte:#04x} decodes as {marker:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        assert!(skip_value(&mut [0xd9, 4, b'a'].as_slice()).is_err());
+        assert!(skip_value(&mut [0x92, 0xc0].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,495 @@
+use crate::{
+    read_array_len, read_format, skip_value, unexpected, BasicTypeKind, Format, FromMsgpack,
+    FromMsgpackError, Len, Neovim, PathSegment, ToMsgpack, ToMsgpackError,
+};
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const REQUEST: i64 = 0;
+const RESPONSE: i64 = 1;
+const NOTIFICATION: i64 = 2;
+
+/// How often to poll an embedded child for exit while closing.
+const REAP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A MsgPack-RPC connection to a Neovim instance.
+pub struct Session<R: Read, W: Write> {
+    reader: Counted<R>,
+    writer: W,
+    child: Embedded,
+    next_msgid: u32,
+    /// Stream position of the start of the message being read.
+    message_start: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    ToMsgpack(#[from] ToMsgpackError),
+    #[error("{0}")]
+    FromMsgpack(#[from] FromMsgpackError),
+    #[error("{0}")]
+    Rpc(#[from] RpcError),
+    #[error("Missing nvim stdin")]
+    NvimStdin,
+    #[error("Missing nvim stdout")]
+    NvimStdout,
+}
+
+/// An error returned by Neovim in response to a request.
+#[derive(Debug, thiserror::Error)]
+#[error("Neovim error {kind}: {message}")]
+pub struct RpcError {
+    pub kind: i64,
+    pub message: String,
+}
+
+impl Session<BufReader<ChildStdout>, BufWriter<ChildStdin>> {
+    /// Spawns `command`, which should start Neovim with `--embed`, and
+    /// communicates with it over its stdio. The child is reaped when the
+    /// session is closed or dropped.
+    pub fn spawn(command: &mut Command) -> Result<Self, SessionError> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            (stdin, _) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(if stdin.is_none() {
+                    SessionError::NvimStdin
+                } else {
+                    SessionError::NvimStdout
+                });
+            }
+        };
+        let mut session = Self::new(BufReader::new(stdout), BufWriter::new(stdin));
+        session.child = Embedded(Some(child));
+        Ok(session)
+    }
+}
+
+impl<R: Read, W: Write> Session<R, W> {
+    /// Creates a session over an existing connection, such as a socket.
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: Counted {
+                inner: reader,
+                position: 0,
+            },
+            writer,
+            child: Embedded(None),
+            next_msgid: 0,
+            message_start: 0,
+        }
+    }
+
+    /// Calls `method` and waits for its result. `arguments` must write the
+    /// parameters as a single MsgPack array.
+    pub fn request<T: FromMsgpack>(
+        &mut self,
+        method: &str,
+        arguments: impl FnOnce(&mut W) -> Result<(), ToMsgpackError>,
+    ) -> Result<T, SessionError> {
+        let msgid = self.next_msgid;
+        self.next_msgid = self.next_msgid.wrapping_add(1);
+        write_request(&mut self.writer, msgid, method, arguments)?;
+        self.writer.flush()?;
+        self.read_result(msgid, method)
+            .map_err(|e| self.with_position(e))
+    }
+
+    fn read_result<T: FromMsgpack>(&mut self, msgid: u32, method: &str) -> Result<T, SessionError> {
+        loop {
+            let Some((id, error)) = self.read_response_header()? else {
+                continue;
+            };
+            if id != msgid || error.is_some() {
+                skip_value(&mut self.reader)?;
+            }
+            if id == msgid {
+                return match error {
+                    Some(error) => Err(error.into()),
+                    None => T::from_msgpack(&mut self.reader)
+                        .map_err(|e| e.within(PathSegment::Result(method.to_owned())).into()),
+                };
+            }
+        }
+    }
+
+    /// Sends `method` without waiting for a result. `arguments` must write
+    /// the parameters as a single MsgPack array.
+    pub fn notify(
+        &mut self,
+        method: &str,
+        arguments: impl FnOnce(&mut W) -> Result<(), ToMsgpackError>,
+    ) -> Result<(), SessionError> {
+        write_notification(&mut self.writer, method, arguments)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Shuts the session down, consuming it so that no further calls can be
+    /// made, and flushes the writer.
+    ///
+    /// Requests are answered before [`Session::request`] returns, so nothing
+    /// is left in flight and no further reads are made. For an embedded
+    /// child, `:qa!` is sent and both pipes are closed, so that the child
+    /// sees EOF and is never left blocked writing output nobody reads. It is
+    /// then given `timeout` to exit before it is killed. Returns the child's
+    /// exit status, if there is one.
+    pub fn close(self, timeout: Duration) -> Result<Option<ExitStatus>, SessionError> {
+        let deadline = Instant::now() + timeout;
+        let Self {
+            reader,
+            mut writer,
+            mut child,
+            ..
+        } = self;
+        let Some(mut child) = child.0.take() else {
+            writer.flush()?;
+            return Ok(None);
+        };
+
+        // The child may already have gone away, in which case writing fails
+        // and reaping below picks up its exit status regardless.
+        let _ = write_notification(&mut writer, "nvim_command", |w| {
+            rmp::encode::write_array_len(w, 1)?;
+            "qa!".to_msgpack(w)
+        });
+        let _ = writer.flush();
+        drop(writer);
+        drop(reader);
+        Ok(Some(reap(&mut child, deadline)?))
+    }
+
+    /// Records on decode errors how far into the current message they occurred.
+    fn with_position(&self, error: SessionError) -> SessionError {
+        match error {
+            SessionError::FromMsgpack(e) => {
+                let offset = self.reader.position - self.message_start - e.consumed();
+                e.at(offset).into()
+            }
+            e => e,
+        }
+    }
+
+    /// Reads the start of the next message. Responses are read up to their
+    /// result, which is left in the stream. Requests and notifications from
+    /// Neovim are skipped entirely.
+    fn read_response_header(&mut self) -> Result<Option<(u32, Option<RpcError>)>, SessionError> {
+        self.message_start = self.reader.position;
+        let r = &mut self.reader;
+        let len = read_array_len(r)?;
+        let kind = i64::from_msgpack(r)?;
+        match (kind, len) {
+            (RESPONSE, 4) => {
+                let msgid = i64::from_msgpack(r)? as u32;
+                let error = read_rpc_error(r)?;
+                Ok(Some((msgid, error)))
+            }
+            _ => {
+                for _ in 1..len {
+                    skip_value(r)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// An embedded child process, killed and reaped when dropped so that it
+/// never outlives its session.
+struct Embedded(Option<Child>);
+
+impl Drop for Embedded {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.0.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl<R: Read, W: Write> Neovim for Session<R, W> {
+    type R = R;
+    type W = W;
+
+    fn call<Return: FromMsgpack>(
+        &mut self,
+        method: &str,
+        argument_writer: impl Fn(&mut Self::W),
+    ) -> Result<Return, SessionError> {
+        self.request(method, |w| {
+            argument_writer(w);
+            Ok(())
+        })
+    }
+}
+
+/// Tracks how far into the stream decoding has progressed.
+struct Counted<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    // Forwarded so that readers with a fast path for small exact reads, as
+    // with most single-value decodes, keep it.
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn write_request<W: Write>(
+    w: &mut W,
+    msgid: u32,
+    method: &str,
+    arguments: impl FnOnce(&mut W) -> Result<(), ToMsgpackError>,
+) -> Result<(), ToMsgpackError> {
+    rmp::encode::write_array_len(w, 4)?;
+    REQUEST.to_msgpack(w)?;
+    (msgid as i64).to_msgpack(w)?;
+    method.to_msgpack(w)?;
+    arguments(w)
+}
+
+fn write_notification<W: Write>(
+    w: &mut W,
+    method: &str,
+    arguments: impl FnOnce(&mut W) -> Result<(), ToMsgpackError>,
+) -> Result<(), ToMsgpackError> {
+    rmp::encode::write_array_len(w, 3)?;
+    NOTIFICATION.to_msgpack(w)?;
+    method.to_msgpack(w)?;
+    arguments(w)
+}
+
+fn read_rpc_error(r: &mut impl Read) -> Result<Option<RpcError>, FromMsgpackError> {
+    match read_format(r)? {
+        (_, Format::Nil) => Ok(None),
+        (_, Format::Array(Len::Fixed(2))) => Ok(Some(RpcError {
+            kind: i64::from_msgpack(r)?,
+            message: String::from_msgpack(r)?,
+        })),
+        (byte, _) => Err(unexpected(BasicTypeKind::Array, byte)),
+    }
+}
+
+fn reap(child: &mut Child, deadline: Instant) -> io::Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            // Wait even if killing fails, for instance because the child
+            // exited in the meantime, so that it is always reaped.
+            let _ = child.kill();
+            return child.wait();
+        }
+        thread::sleep(REAP_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn session(input: Vec<u8>) -> Session<Cursor<Vec<u8>>, Vec<u8>> {
+        Session::new(Cursor::new(input), vec![])
+    }
+
+    fn no_arguments(w: &mut Vec<u8>) -> Result<(), ToMsgpackError> {
+        rmp::encode::write_array_len(w, 0)?;
+        Ok(())
+    }
+
+    fn response_header(w: &mut Vec<u8>, msgid: u32) {
+        rmp::encode::write_array_len(w, 4).unwrap();
+        rmp::encode::write_uint(w, RESPONSE as u64).unwrap();
+        rmp::encode::write_uint(w, msgid as u64).unwrap();
+        rmp::encode::write_nil(w).unwrap();
+    }
+
+    fn response(w: &mut Vec<u8>, msgid: u32, result: i64) {
+        response_header(w, msgid);
+        rmp::encode::write_sint(w, result).unwrap();
+    }
+
+    #[test]
+    fn decodes_large_msgids_and_results() {
+        let results = [127, 128, 300, -33, -200, 70_000, i64::MAX, i64::MIN];
+        let mut input = vec![];
+        for (i, &result) in results.iter().enumerate() {
+            response(&mut input, 200 + i as u32, result);
+        }
+        let mut session = session(input);
+        session.next_msgid = 200;
+        for result in results {
+            assert_eq!(session.request::<i64>("m", no_arguments).unwrap(), result);
+        }
+
+        let mut written = session.writer.as_slice();
+        assert_eq!(read_array_len(&mut written).unwrap(), 4);
+        assert_eq!(i64::from_msgpack(&mut written).unwrap(), REQUEST);
+        assert_eq!(i64::from_msgpack(&mut written).unwrap(), 200);
+        assert_eq!(String::from_msgpack(&mut written).unwrap(), "m");
+    }
+
+    #[test]
+    fn decodes_float_results() {
+        let mut input = vec![];
+        response_header(&mut input, 0);
+        rmp::encode::write_f32(&mut input, 1.5).unwrap();
+        response_header(&mut input, 1);
+        rmp::encode::write_f64(&mut input, -2.25).unwrap();
+        let mut session = session(input);
+        assert_eq!(session.request::<f64>("m", no_arguments).unwrap(), 1.5);
+        assert_eq!(session.request::<f64>("m", no_arguments).unwrap(), -2.25);
+    }
+
+    #[test]
+    fn returns_rpc_errors() {
+        let mut input = vec![];
+        rmp::encode::write_array_len(&mut input, 4).unwrap();
+        rmp::encode::write_uint(&mut input, RESPONSE as u64).unwrap();
+        rmp::encode::write_uint(&mut input, 0).unwrap();
+        rmp::encode::write_array_len(&mut input, 2).unwrap();
+        rmp::encode::write_uint(&mut input, 1).unwrap();
+        rmp::encode::write_str(&mut input, "Invalid buffer id: 300").unwrap();
+        rmp::encode::write_nil(&mut input).unwrap();
+        response(&mut input, 1, 5);
+
+        let mut session = session(input);
+        match session.request::<i64>("m", no_arguments) {
+            Err(SessionError::Rpc(RpcError { kind, message })) => {
+                assert_eq!(kind, 1);
+                assert_eq!(message, "Invalid buffer id: 300");
+            }
+            other => panic!("expected an RPC error, got {other:?}"),
+        }
+        // The rest of the errored response was consumed
+        assert_eq!(session.request::<i64>("m", no_arguments).unwrap(), 5);
+    }
+
+    #[test]
+    fn skips_messages_from_neovim_between_responses() {
+        let mut input = vec![];
+        rmp::encode::write_array_len(&mut input, 3).unwrap();
+        rmp::encode::write_uint(&mut input, NOTIFICATION as u64).unwrap();
+        rmp::encode::write_str(&mut input, "redraw").unwrap();
+        rmp::encode::write_array_len(&mut input, 1).unwrap();
+        rmp::encode::write_array_len(&mut input, 2).unwrap();
+        rmp::encode::write_str(&mut input, "flush").unwrap();
+        rmp::encode::write_array_len(&mut input, 0).unwrap();
+
+        rmp::encode::write_array_len(&mut input, 4).unwrap();
+        rmp::encode::write_uint(&mut input, REQUEST as u64).unwrap();
+        rmp::encode::write_uint(&mut input, 7).unwrap();
+        rmp::encode::write_str(&mut input, "handler").unwrap();
+        rmp::encode::write_array_len(&mut input, 2).unwrap();
+        rmp::encode::write_map_len(&mut input, 1).unwrap();
+        rmp::encode::write_str(&mut input, "key").unwrap();
+        rmp::encode::write_f64(&mut input, 1.0).unwrap();
+        rmp::encode::write_bool(&mut input, true).unwrap();
+
+        response(&mut input, 9, 1);
+        response(&mut input, 0, 2);
+        response(&mut input, 1, 3);
+
+        let mut session = session(input);
+        assert_eq!(session.request::<i64>("m", no_arguments).unwrap(), 2);
+        assert_eq!(session.request::<i64>("m", no_arguments).unwrap(), 3);
+    }
+
+    #[test]
+    fn reports_offsets_within_the_failing_message() {
+        let mut input = vec![];
+        rmp::encode::write_array_len(&mut input, 3).unwrap();
+        rmp::encode::write_uint(&mut input, NOTIFICATION as u64).unwrap();
+        rmp::encode::write_str(&mut input, "redraw").unwrap();
+        rmp::encode::write_array_len(&mut input, 0).unwrap();
+        response_header(&mut input, 0);
+        rmp::encode::write_array_len(&mut input, 2).unwrap();
+        rmp::encode::write_sint(&mut input, 1).unwrap();
+        rmp::encode::write_str(&mut input, "oops").unwrap();
+
+        let mut session = session(input);
+        let Err(SessionError::FromMsgpack(e)) = session.request::<Vec<i64>>("m", no_arguments)
+        else {
+            panic!("expected a decode error");
+        };
+        // Header (4 bytes), array marker, and integer precede the string
+        assert_eq!(e.context().unwrap().offset, Some(6));
+        assert_eq!(
+            e.to_string(),
+            "Unexpected MsgPack type: expected Integer, found FixStr(4), \
+            while decoding element 1 of the result of m at byte 6 of the message"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn close_sends_quit_and_reaps_child() {
+        let path = std::env::temp_dir().join(format!("nvim-sys-close-{}", std::process::id()));
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("cat > \"$0\"").arg(&path);
+        let session = Session::spawn(&mut command).unwrap();
+
+        let start = Instant::now();
+        let status = session.close(Duration::from_secs(5)).unwrap().unwrap();
+        assert!(status.success());
+        // The child exits on EOF rather than being killed at the deadline
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut expected = vec![];
+        write_notification(&mut expected, "nvim_command", |w| {
+            rmp::encode::write_array_len(w, 1)?;
+            "qa!".to_msgpack(w)
+        })
+        .unwrap();
+        assert_eq!(written, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn close_kills_child_after_timeout() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let session = Session::spawn(&mut command).unwrap();
+
+        let start = Instant::now();
+        let status = session.close(Duration::from_millis(200)).unwrap().unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(status.signal(), Some(9));
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn close_without_child() {
+        let session = session(vec![]);
+        assert!(session.close(Duration::ZERO).unwrap().is_none());
+    }
+}
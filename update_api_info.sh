@@ -0,0 +1,3 @@
+#! /usr/bin/env sh
+
+nvim --api-info > api_info.mpack